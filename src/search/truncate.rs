@@ -4,14 +4,140 @@
 //!
 //! All detection is line-by-line text matching; no tree-sitter needed.
 
+use std::collections::HashSet;
+
 use crate::types::Lang;
 
 /// Minimum function size (in lines) before smart truncation kicks in.
 const SMART_TRUNCATE_MIN_LINES: u32 = 80;
 
+/// Default trade-off between relevance and diversity for
+/// [`select_diverse_lines_mmr`]. Higher favors raw score, lower favors spread.
+const MMR_DEFAULT_LAMBDA: f64 = 0.7;
+
+/// Highest score a non-forced line can get from [`score_line`] (forced
+/// signature/closing lines score 100 and never reach MMR's candidate list).
+/// Used to normalize score onto the same `[0, 1]` scale as `sim` in
+/// [`select_diverse_lines_mmr`], so `lambda` trades off relevance and
+/// diversity consistently instead of score dwarfing similarity at every
+/// non-tied comparison.
+const MAX_CANDIDATE_LINE_SCORE: f64 = 10.0;
+
 /// Maximum number of lines to keep after truncation.
 const SMART_TRUNCATE_MAX_LINES: usize = 40;
 
+/// Hard cap on the number of candidate lines fed into the knapsack DP in
+/// [`select_diverse_lines_budgeted`]. The DP's `choice` backpointer table is
+/// `O(candidates * max_score)`, and `max_score` itself grows with the number
+/// of candidates, so an uncapped candidate list makes both time and memory
+/// quadratic in function size — a multi-thousand-line generated file can
+/// stall for seconds and allocate hundreds of MB. Capping to the top-scoring
+/// candidates keeps the DP bounded regardless of input size; lines dropped
+/// by the cap are simply never considered, which only matters for functions
+/// far larger than what smart truncation is meant to summarize anyway.
+const BUDGETED_KNAPSACK_MAX_CANDIDATES: usize = 400;
+
+/// Per-language keyword/pattern tables used by [`score_line`].
+///
+/// Each field lists the patterns that mark a line as belonging to that
+/// category in a given language. `starts`/`contains`/`ends` name how the
+/// pattern is matched against the trimmed line; `exact` requires an exact
+/// match (used for bare keywords like `else` or `loop`).
+struct ScoringProfile {
+    control_flow_starts: &'static [&'static str],
+    control_flow_exact: &'static [&'static str],
+    control_flow_contains: &'static [&'static str],
+    error_starts: &'static [&'static str],
+    error_contains: &'static [&'static str],
+    error_ends: &'static [&'static str],
+    var_decl_starts: &'static [&'static str],
+    var_decl_contains: &'static [&'static str],
+    comment_starts: &'static [&'static str],
+    comment_exact: &'static [&'static str],
+}
+
+/// Fallback profile for languages without a dedicated table, and for Rust
+/// (whose control flow / error handling idioms match this table directly).
+static GENERIC_PROFILE: ScoringProfile = ScoringProfile {
+    control_flow_starts: &[
+        "if ", "} else", "else ", "else{", "match ", "switch ", "case ", "for ", "while ",
+        "loop ", "loop{", "return ", "return;",
+    ],
+    control_flow_exact: &["else", "loop", "return"],
+    control_flow_contains: &[],
+    error_starts: &["catch ", "catch("],
+    error_contains: &[".unwrap()", ".expect(", "panic!(", "bail!(", "anyhow!("],
+    error_ends: &["?;", "?"],
+    var_decl_starts: &["let ", "const ", "var ", "mut "],
+    var_decl_contains: &[],
+    comment_starts: &["//", "#", "/*", "* "],
+    comment_exact: &["*/", "*"],
+};
+
+/// Python: `except`/`raise`/`with` for error handling, `def` for declarations,
+/// `#` comments, no braces.
+static PYTHON_PROFILE: ScoringProfile = ScoringProfile {
+    control_flow_starts: &[
+        "if ", "elif ", "else:", "else ", "for ", "while ", "return ", "return",
+    ],
+    control_flow_exact: &["else", "return", "break", "continue"],
+    control_flow_contains: &[],
+    error_starts: &["except ", "except:", "raise ", "raise", "with "],
+    error_contains: &[],
+    error_ends: &[],
+    var_decl_starts: &["def ", "async def "],
+    var_decl_contains: &["="],
+    comment_starts: &["#"],
+    comment_exact: &[],
+};
+
+/// Go: `if err != nil` and `defer` for error handling, `go `/`:=` for
+/// declarations and concurrency, `//` comments.
+static GO_PROFILE: ScoringProfile = ScoringProfile {
+    control_flow_starts: &[
+        "if ", "} else", "else ", "else{", "switch ", "case ", "for ", "return ", "return",
+    ],
+    control_flow_exact: &["else", "return", "break", "continue"],
+    control_flow_contains: &[],
+    error_starts: &["defer "],
+    error_contains: &["if err != nil", "panic("],
+    error_ends: &[],
+    var_decl_starts: &["var ", "const ", "go "],
+    var_decl_contains: &[":="],
+    comment_starts: &["//", "/*", "* "],
+    comment_exact: &["*/", "*"],
+};
+
+/// JavaScript/TypeScript: `throw`/`await`/`catch` for error handling,
+/// `=>`/`async` as signal-bearing constructs, `//` comments.
+static JS_PROFILE: ScoringProfile = ScoringProfile {
+    control_flow_starts: &[
+        "if ", "} else", "else ", "else{", "switch ", "case ", "for ", "while ", "return ",
+        "return;",
+    ],
+    control_flow_exact: &["else", "return"],
+    control_flow_contains: &["=>"],
+    error_starts: &["catch ", "catch(", "throw "],
+    error_contains: &["await ", "async ", ".catch(", ".then("],
+    error_ends: &[],
+    var_decl_starts: &["let ", "const ", "var "],
+    var_decl_contains: &[],
+    comment_starts: &["//", "/*", "* "],
+    comment_exact: &["*/", "*"],
+};
+
+/// Look up the [`ScoringProfile`] for a language. Unrecognized languages fall
+/// back to [`GENERIC_PROFILE`], so adding a new language is a data change:
+/// add a table above and a match arm here.
+fn profile_for(lang: Lang) -> &'static ScoringProfile {
+    match lang {
+        Lang::Python => &PYTHON_PROFILE,
+        Lang::Go => &GO_PROFILE,
+        Lang::JavaScript | Lang::TypeScript => &JS_PROFILE,
+        _ => &GENERIC_PROFILE,
+    }
+}
+
 /// Select diverse/important lines from a function body.
 ///
 /// Returns `None` if the range is smaller than [`SMART_TRUNCATE_MIN_LINES`]
@@ -21,12 +147,13 @@ pub(crate) fn select_diverse_lines(
     content: &str,
     start: u32,
     end: u32,
-    _lang: Lang,
+    lang: Lang,
 ) -> Option<Vec<u32>> {
     if end.saturating_sub(start) < SMART_TRUNCATE_MIN_LINES {
         return None;
     }
 
+    let profile = profile_for(lang);
     let lines: Vec<&str> = content.lines().collect();
     let mut scored: Vec<(u32, u32)> = Vec::new(); // (line_number, score)
 
@@ -37,7 +164,7 @@ pub(crate) fn select_diverse_lines(
             None => break,
         };
         let trimmed = line.trim();
-        let score = score_line(trimmed, line_num, start, end);
+        let score = score_line(trimmed, line_num, start, end, profile);
         scored.push((line_num, score));
     }
 
@@ -53,9 +180,400 @@ pub(crate) fn select_diverse_lines(
     Some(scored.into_iter().map(|(line, _)| line).collect())
 }
 
+/// Estimate the token cost of a line. Uses a cheap `chars / 4` heuristic
+/// rather than a real tokenizer, which is accurate enough for budgeting
+/// purposes and avoids pulling in a tokenizer dependency here.
+fn estimate_tokens(line: &str) -> u32 {
+    let chars = line.chars().count() as u32;
+    chars.div_ceil(4).max(1)
+}
+
+/// Select diverse/important lines from a function body under a token budget,
+/// rather than a fixed line cap.
+///
+/// Like [`select_diverse_lines`], but instead of keeping the top
+/// [`SMART_TRUNCATE_MAX_LINES`] lines by score, this solves a 0/1 knapsack:
+/// maximize total score subject to total estimated token cost `<= token_budget`.
+/// The signature and closing-brace lines (score 100) are always kept first,
+/// with their cost subtracted from the budget before the DP runs.
+///
+/// The DP table is indexed by achievable *score*, not by `token_budget`
+/// (tokens). Raw-cost indexing would make the table's size proportional to
+/// `token_budget`, which for a realistic budget (thousands of tokens) would
+/// mean tens to hundreds of MB — or much worse — per call. Score is bounded
+/// by `10 * candidates.len()` (each non-forced line scores at most 10, see
+/// [`score_line`]), which does not grow with the budget at all, so the DP is `dp[score] = min cost to achieve that score`
+/// instead of `dp[cost] = max score for that cost`. Candidates are also
+/// capped at [`BUDGETED_KNAPSACK_MAX_CANDIDATES`] before the DP runs, since
+/// `max_score` (and therefore the DP table) grows with the candidate count —
+/// without a cap, a large enough function still makes the DP quadratic.
+///
+/// Returns `None` if the range is smaller than [`SMART_TRUNCATE_MIN_LINES`].
+/// Otherwise returns `Some(vec)` of 1-based line numbers to KEEP, sorted
+/// ascending.
+pub(crate) fn select_diverse_lines_budgeted(
+    content: &str,
+    start: u32,
+    end: u32,
+    lang: Lang,
+    token_budget: u32,
+) -> Option<Vec<u32>> {
+    if end.saturating_sub(start) < SMART_TRUNCATE_MIN_LINES {
+        return None;
+    }
+
+    let profile = profile_for(lang);
+    let lines: Vec<&str> = content.lines().collect();
+
+    let mut forced: Vec<u32> = Vec::new();
+    let mut candidates: Vec<(u32, u32, u32)> = Vec::new(); // (line_number, score, cost)
+    let mut remaining_budget = token_budget;
+
+    for line_num in start..=end {
+        let idx = (line_num - 1) as usize;
+        let line = match lines.get(idx) {
+            Some(l) => *l,
+            None => break,
+        };
+        let trimmed = line.trim();
+        let score = score_line(trimmed, line_num, start, end, profile);
+        let cost = estimate_tokens(line);
+
+        if score == 100 {
+            forced.push(line_num);
+            remaining_budget = remaining_budget.saturating_sub(cost);
+        } else {
+            candidates.push((line_num, score, cost));
+        }
+    }
+
+    // Bound the DP below by pre-filtering to the top-scoring candidates, so
+    // its cost stays bounded even for very large functions (see
+    // `BUDGETED_KNAPSACK_MAX_CANDIDATES`). Re-sort by line number afterward so
+    // the forced/candidate merge and the DP's own ordering stay line-ordered.
+    if candidates.len() > BUDGETED_KNAPSACK_MAX_CANDIDATES {
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        candidates.truncate(BUDGETED_KNAPSACK_MAX_CANDIDATES);
+        candidates.sort_by_key(|&(line_num, _, _)| line_num);
+    }
+
+    let n = candidates.len();
+    let max_score: usize = candidates.iter().map(|&(_, score, _)| score as usize).sum();
+
+    // dp[s] = minimum total cost to achieve score exactly `s` using a subset
+    // of the candidates processed so far. Unreachable scores stay at u32::MAX.
+    let mut dp: Vec<u32> = vec![u32::MAX; max_score + 1];
+    dp[0] = 0;
+    // choice[i][s] = true if candidate i was taken to reach dp[s] at step i.
+    let mut choice: Vec<Vec<bool>> = vec![vec![false; max_score + 1]; n];
+
+    for (i, &(_, score, cost)) in candidates.iter().enumerate() {
+        let score = score as usize;
+        for s in (score..=max_score).rev() {
+            if dp[s - score] != u32::MAX {
+                let candidate_cost = dp[s - score] + cost;
+                if candidate_cost < dp[s] {
+                    dp[s] = candidate_cost;
+                    choice[i][s] = true;
+                }
+            }
+        }
+    }
+
+    // The best achievable score is the highest `s` whose minimum cost fits
+    // the remaining budget.
+    let best_score = dp
+        .iter()
+        .rposition(|&cost| cost <= remaining_budget)
+        .unwrap_or(0);
+
+    // Recover the chosen candidates by walking the choice table backwards.
+    let mut kept = forced;
+    let mut s = best_score;
+    for i in (0..n).rev() {
+        if choice[i][s] {
+            let (line_num, score, _) = candidates[i];
+            kept.push(line_num);
+            s -= score as usize;
+        }
+    }
+
+    kept.sort_unstable();
+    Some(kept)
+}
+
+/// Select diverse/important lines using Maximal Marginal Relevance, trading
+/// off per-line score against similarity to lines already kept.
+///
+/// Unlike [`select_diverse_lines`], which greedily keeps the top-scoring
+/// lines regardless of clustering, this iteratively picks the unselected
+/// line `i` that maximizes `lambda * score(i) - (1 - lambda) * max_sim(i, selected)`,
+/// where `score(i)` is normalized onto `[0, 1]` (see [`MAX_CANDIDATE_LINE_SCORE`])
+/// so it's on the same scale as `max_sim`, which combines positional
+/// proximity and token-level textual overlap. This spreads the kept lines
+/// across the whole function body instead of clustering around one hot spot.
+/// The signature and closing lines are pre-selected so they anchor the
+/// spread and are never displaced.
+///
+/// `lambda` is typically in `[0.0, 1.0]`; use [`MMR_DEFAULT_LAMBDA`] (~0.7)
+/// when the caller has no stronger opinion, or call
+/// [`select_diverse_lines_mmr_default`] directly.
+///
+/// Returns `None` if the range is smaller than [`SMART_TRUNCATE_MIN_LINES`].
+/// Otherwise returns `Some(vec)` of 1-based line numbers to KEEP, sorted
+/// ascending.
+pub(crate) fn select_diverse_lines_mmr(
+    content: &str,
+    start: u32,
+    end: u32,
+    lang: Lang,
+    lambda: f64,
+) -> Option<Vec<u32>> {
+    if end.saturating_sub(start) < SMART_TRUNCATE_MIN_LINES {
+        return None;
+    }
+
+    let profile = profile_for(lang);
+    let lines: Vec<&str> = content.lines().collect();
+    let span = f64::from(end.saturating_sub(start).max(1));
+
+    struct Candidate<'a> {
+        line_num: u32,
+        /// Normalized to `[0, 1]` via [`MAX_CANDIDATE_LINE_SCORE`] so it's
+        /// comparable to `sim`, which is also `[0, 1]`.
+        normalized_score: f64,
+        tokens: HashSet<&'a str>,
+    }
+
+    let mut forced: Vec<u32> = Vec::new();
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for line_num in start..=end {
+        let idx = (line_num - 1) as usize;
+        let line = match lines.get(idx) {
+            Some(l) => *l,
+            None => break,
+        };
+        let trimmed = line.trim();
+        let score = score_line(trimmed, line_num, start, end, profile);
+
+        if score == 100 {
+            forced.push(line_num);
+        } else {
+            candidates.push(Candidate {
+                line_num,
+                normalized_score: f64::from(score) / MAX_CANDIDATE_LINE_SCORE,
+                tokens: trimmed.split_whitespace().collect(),
+            });
+        }
+    }
+
+    // sim(i, j) averages positional proximity and token Jaccard overlap.
+    let sim = |a: &Candidate, b_line: u32, b_tokens: &HashSet<&str>| -> f64 {
+        let dist = f64::from(a.line_num.abs_diff(b_line));
+        let positional = 1.0 / (1.0 + dist / span);
+
+        let intersection = a.tokens.intersection(b_tokens).count();
+        let union = a.tokens.union(b_tokens).count();
+        let jaccard = if union == 0 {
+            0.0
+        } else {
+            intersection as f64 / union as f64
+        };
+
+        0.5 * positional + 0.5 * jaccard
+    };
+
+    let mut selected: Vec<(u32, HashSet<&str>)> = forced
+        .iter()
+        .map(|&ln| (ln, HashSet::new()))
+        .collect();
+    let mut kept = forced;
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+
+    while !remaining.is_empty() && kept.len() < SMART_TRUNCATE_MAX_LINES {
+        let mut best_idx_pos = 0;
+        let mut best_mmr = f64::NEG_INFINITY;
+
+        for (pos, &idx) in remaining.iter().enumerate() {
+            let candidate = &candidates[idx];
+            let max_sim = selected
+                .iter()
+                .map(|(ln, tokens)| sim(candidate, *ln, tokens))
+                .fold(0.0_f64, f64::max);
+            let mmr = lambda * candidate.normalized_score - (1.0 - lambda) * max_sim;
+
+            if mmr > best_mmr {
+                best_mmr = mmr;
+                best_idx_pos = pos;
+            }
+        }
+
+        let idx = remaining.swap_remove(best_idx_pos);
+        let candidate = &candidates[idx];
+        kept.push(candidate.line_num);
+        selected.push((candidate.line_num, candidate.tokens.clone()));
+    }
+
+    kept.sort_unstable();
+    Some(kept)
+}
+
+/// [`select_diverse_lines_mmr`] with [`MMR_DEFAULT_LAMBDA`], for callers with
+/// no opinion on the relevance/diversity trade-off.
+pub(crate) fn select_diverse_lines_mmr_default(
+    content: &str,
+    start: u32,
+    end: u32,
+    lang: Lang,
+) -> Option<Vec<u32>> {
+    select_diverse_lines_mmr(content, start, end, lang, MMR_DEFAULT_LAMBDA)
+}
+
+/// A contiguous, inclusive, 1-based line range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LineRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Output mode for [`render_truncation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RenderMode {
+    /// Kept source blocks interleaved with `// … N lines omitted …` markers.
+    Pretty,
+    /// Just the ranges, e.g. `L11-L14, L21, L31-L33`.
+    Terse,
+    /// `{kept_ranges, omitted_ranges, original_lines}` for tool integration.
+    Json,
+}
+
+/// Coalesce sorted, deduplicated 1-based line numbers into contiguous ranges.
+fn merge_into_ranges(kept: &[u32]) -> Vec<LineRange> {
+    let mut ranges: Vec<LineRange> = Vec::new();
+    for &line in kept {
+        match ranges.last_mut() {
+            Some(r) if line == r.end + 1 => r.end = line,
+            _ => ranges.push(LineRange {
+                start: line,
+                end: line,
+            }),
+        }
+    }
+    ranges
+}
+
+/// The gaps between consecutive kept ranges. Lines before the first kept range
+/// or after the last one are not included.
+fn omitted_ranges(kept_ranges: &[LineRange]) -> Vec<LineRange> {
+    kept_ranges
+        .windows(2)
+        .filter_map(|w| {
+            let gap_start = w[0].end + 1;
+            let gap_end = w[1].start - 1;
+            (gap_start <= gap_end).then_some(LineRange {
+                start: gap_start,
+                end: gap_end,
+            })
+        })
+        .collect()
+}
+
+/// Render a kept-line selection (as returned by one of the
+/// `select_diverse_lines*` functions) for display or tool consumption.
+///
+/// `kept` must be sorted ascending, 1-based line numbers into `content`.
+/// `original_lines` is the true line count of the pre-truncation content (or
+/// function range) that `kept` was selected from — it is NOT derived from
+/// `kept`'s own span, since `kept` may not touch the original boundaries (the
+/// caller may pass a `kept` slice that doesn't include the first or last
+/// line of the range it was selected from).
+/// Gap-merges `kept` into contiguous ranges first, then renders per `mode`:
+/// - [`RenderMode::Pretty`] interleaves the kept source with
+///   `// … N lines omitted …` markers, matching how test runners elide
+///   passing output.
+/// - [`RenderMode::Terse`] emits only the ranges, e.g. `L11-L14, L21, L31-L33`.
+/// - [`RenderMode::Json`] emits `{kept_ranges, omitted_ranges, original_lines}`
+///   for machine consumption.
+///
+/// Returns an empty string if `kept` is empty.
+pub(crate) fn render_truncation(
+    content: &str,
+    kept: &[u32],
+    original_lines: u32,
+    mode: RenderMode,
+) -> String {
+    if kept.is_empty() {
+        return String::new();
+    }
+
+    let kept_ranges = merge_into_ranges(kept);
+    let omitted = omitted_ranges(&kept_ranges);
+
+    match mode {
+        RenderMode::Pretty => {
+            let lines: Vec<&str> = content.lines().collect();
+            render_pretty(&lines, &kept_ranges, &omitted)
+        }
+        RenderMode::Terse => render_terse(&kept_ranges),
+        RenderMode::Json => render_json(&kept_ranges, &omitted, original_lines),
+    }
+}
+
+fn render_pretty(lines: &[&str], kept_ranges: &[LineRange], omitted: &[LineRange]) -> String {
+    let mut out = String::new();
+    for (i, range) in kept_ranges.iter().enumerate() {
+        for line_num in range.start..=range.end {
+            let idx = (line_num - 1) as usize;
+            if let Some(line) = lines.get(idx) {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        if let Some(gap) = omitted.get(i) {
+            let gap_len = gap.end - gap.start + 1;
+            out.push_str("// … ");
+            out.push_str(&gap_len.to_string());
+            out.push_str(" lines omitted …\n");
+        }
+    }
+    out
+}
+
+fn render_terse(kept_ranges: &[LineRange]) -> String {
+    kept_ranges
+        .iter()
+        .map(|r| {
+            if r.start == r.end {
+                format!("L{}", r.start)
+            } else {
+                format!("L{}-L{}", r.start, r.end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn render_json(kept_ranges: &[LineRange], omitted: &[LineRange], original_lines: u32) -> String {
+    let format_ranges = |ranges: &[LineRange]| -> String {
+        ranges
+            .iter()
+            .map(|r| format!("[{},{}]", r.start, r.end))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    format!(
+        "{{\"kept_ranges\":[{}],\"omitted_ranges\":[{}],\"original_lines\":{}}}",
+        format_ranges(kept_ranges),
+        format_ranges(omitted),
+        original_lines
+    )
+}
+
 /// Score a single line based on its content. Higher scores indicate more
 /// important lines that should be preserved during truncation.
-fn score_line(trimmed: &str, line_num: u32, start: u32, end: u32) -> u32 {
+fn score_line(trimmed: &str, line_num: u32, start: u32, end: u32, profile: &ScoringProfile) -> u32 {
     // Signature and closing brace are always kept
     if line_num == start || line_num == end {
         return 100;
@@ -65,19 +583,19 @@ fn score_line(trimmed: &str, line_num: u32, start: u32, end: u32) -> u32 {
     if trimmed.is_empty() {
         return 0;
     }
-    if is_comment_only(trimmed) {
+    if is_comment_only(trimmed, profile) {
         return 0;
     }
 
     let mut score: u32 = 0;
 
     // Control flow keywords (score 10)
-    if is_control_flow(trimmed) {
+    if is_control_flow(trimmed, profile) {
         score = score.max(10);
     }
 
     // Error handling (score 10)
-    if is_error_handling(trimmed) {
+    if is_error_handling(trimmed, profile) {
         score = score.max(10);
     }
 
@@ -97,56 +615,39 @@ fn score_line(trimmed: &str, line_num: u32, start: u32, end: u32) -> u32 {
     }
 
     // Simple assignments / variable declarations (score 1)
-    if score == 0 && (trimmed.contains('=') || is_var_decl(trimmed)) {
+    if score == 0 && (trimmed.contains('=') || is_var_decl(trimmed, profile)) {
         score = 1;
     }
 
     score
 }
 
-/// Returns `true` if the line is comment-only (any common language).
-fn is_comment_only(trimmed: &str) -> bool {
-    trimmed.starts_with("//")
-        || trimmed.starts_with('#')
-        || trimmed.starts_with("/*")
-        || trimmed.starts_with("* ")
-        || trimmed == "*/"
-        || trimmed == "*"
+/// Returns `true` if the line is comment-only, per the language's profile.
+fn is_comment_only(trimmed: &str, profile: &ScoringProfile) -> bool {
+    profile.comment_starts.iter().any(|p| trimmed.starts_with(p))
+        || profile.comment_exact.contains(&trimmed)
 }
 
-/// Returns `true` if the line starts with a control flow keyword.
-fn is_control_flow(trimmed: &str) -> bool {
-    trimmed.starts_with("if ")
-        || trimmed.starts_with("} else")
-        || trimmed.starts_with("else ")
-        || trimmed.starts_with("else{")
-        || trimmed == "else"
-        || trimmed.starts_with("match ")
-        || trimmed.starts_with("switch ")
-        || trimmed.starts_with("case ")
-        || trimmed.starts_with("for ")
-        || trimmed.starts_with("while ")
-        || trimmed.starts_with("loop ")
-        || trimmed.starts_with("loop{")
-        || trimmed == "loop"
-        || trimmed.starts_with("return ")
-        || trimmed == "return"
-        || trimmed.starts_with("return;")
+/// Returns `true` if the line starts with a control flow keyword, per the
+/// language's profile.
+fn is_control_flow(trimmed: &str, profile: &ScoringProfile) -> bool {
+    profile
+        .control_flow_starts
+        .iter()
+        .any(|p| trimmed.starts_with(p))
+        || profile.control_flow_exact.contains(&trimmed)
+        || profile
+            .control_flow_contains
+            .iter()
+            .any(|p| trimmed.contains(p))
 }
 
-/// Returns `true` if the line contains error handling patterns.
-fn is_error_handling(trimmed: &str) -> bool {
-    trimmed.ends_with("?;")
-        || trimmed.ends_with('?')
-        || trimmed.contains(".unwrap()")
-        || trimmed.contains(".expect(")
-        || trimmed.starts_with("catch ")
-        || trimmed.starts_with("catch(")
-        || trimmed.starts_with("except ")
-        || trimmed.starts_with("except:")
-        || trimmed.contains("panic!(")
-        || trimmed.contains("bail!(")
-        || trimmed.contains("anyhow!(")
+/// Returns `true` if the line contains error handling patterns, per the
+/// language's profile.
+fn is_error_handling(trimmed: &str, profile: &ScoringProfile) -> bool {
+    profile.error_ends.iter().any(|p| trimmed.ends_with(p))
+        || profile.error_contains.iter().any(|p| trimmed.contains(p))
+        || profile.error_starts.iter().any(|p| trimmed.starts_with(p))
 }
 
 /// Returns `true` if the line is a plain assignment with no function call.
@@ -154,12 +655,14 @@ fn is_plain_assignment(trimmed: &str) -> bool {
     trimmed.contains('=') && !trimmed.contains('(')
 }
 
-/// Returns `true` if the line starts with a variable declaration keyword.
-fn is_var_decl(trimmed: &str) -> bool {
-    trimmed.starts_with("let ")
-        || trimmed.starts_with("const ")
-        || trimmed.starts_with("var ")
-        || trimmed.starts_with("mut ")
+/// Returns `true` if the line starts with, or contains, a variable declaration
+/// marker, per the language's profile (e.g. Go's `:=` or Python's `=`).
+fn is_var_decl(trimmed: &str, profile: &ScoringProfile) -> bool {
+    profile
+        .var_decl_starts
+        .iter()
+        .any(|p| trimmed.starts_with(p))
+        || profile.var_decl_contains.iter().any(|p| trimmed.contains(p))
 }
 
 #[cfg(test)]
@@ -298,4 +801,317 @@ mod tests {
             "79-line gap should not trigger truncation"
         );
     }
+
+    #[test]
+    fn python_profile_prefers_except_and_with() {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("def example():".to_owned());
+        for _ in 2..=90 {
+            lines.push(String::new());
+        }
+        lines[15] = "    except ValueError:".to_owned(); // line 16
+        lines[25] = "    with open(path) as f:".to_owned(); // line 26
+        lines.push("    return None".to_owned());
+
+        let content = lines.join("\n");
+        let result = select_diverse_lines(&content, 1, 91, Lang::Python).unwrap();
+
+        assert!(result.contains(&16), "except line should be kept");
+        assert!(result.contains(&26), "with line should be kept");
+    }
+
+    #[test]
+    fn go_profile_prefers_err_check() {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("func example() error {".to_owned());
+        for _ in 2..=90 {
+            lines.push(String::new());
+        }
+        lines[15] = "    if err != nil {".to_owned(); // line 16
+        lines[25] = "    defer f.Close()".to_owned(); // line 26
+        lines.push("}".to_owned());
+
+        let content = lines.join("\n");
+        let result = select_diverse_lines(&content, 1, 91, Lang::Go).unwrap();
+
+        assert!(result.contains(&16), "err check should be kept");
+        assert!(result.contains(&26), "defer line should be kept");
+    }
+
+    #[test]
+    fn budgeted_respects_budget_and_keeps_signature() {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn big_function() {".to_owned());
+        for i in 2..=99 {
+            lines.push(format!("    let x{i} = {i};"));
+        }
+        lines.push("}".to_owned());
+        let content = lines.join("\n");
+
+        let result = select_diverse_lines_budgeted(&content, 1, 100, Lang::Rust, 200).unwrap();
+
+        assert!(result.contains(&1), "signature line must be kept");
+        assert!(result.contains(&100), "closing line must be kept");
+
+        let total_cost: u32 = result
+            .iter()
+            .map(|&ln| estimate_tokens(content.lines().nth((ln - 1) as usize).unwrap()))
+            .sum();
+        assert!(
+            total_cost <= 200,
+            "total estimated cost {total_cost} must not exceed the budget"
+        );
+        assert!(result.windows(2).all(|w| w[0] < w[1]), "lines must be sorted");
+    }
+
+    #[test]
+    fn budgeted_prefers_high_score_dense_lines_within_budget() {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn example() {".to_owned());
+        for _ in 2..=90 {
+            lines.push(String::new()); // blank, score 0, cost ~1
+        }
+        lines[15] = "    bar.unwrap();".to_owned(); // line 16, score 10
+        lines.push("}".to_owned());
+        let content = lines.join("\n");
+
+        // Budget tight enough that only a handful of non-forced lines fit.
+        let result = select_diverse_lines_budgeted(&content, 1, 91, Lang::Rust, 20).unwrap();
+
+        assert!(result.contains(&16), "high-score unwrap line should be kept");
+    }
+
+    #[test]
+    fn budgeted_short_function_returns_none() {
+        let content = (1..=50)
+            .map(|i| format!("line {i}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let result = select_diverse_lines_budgeted(&content, 1, 50, Lang::Rust, 1000);
+        assert!(
+            result.is_none(),
+            "functions <80 lines should not be truncated"
+        );
+    }
+
+    #[test]
+    fn budgeted_large_function_with_large_budget_stays_cheap() {
+        // The DP must be sized by achievable score, not by `token_budget`
+        // directly — a large function with a generous token budget should
+        // not allocate a table proportional to raw token count.
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn huge() {".to_owned());
+        for i in 2..=3000 {
+            lines.push(format!("    let x{i} = foo({i});"));
+        }
+        lines.push("}".to_owned());
+        let content = lines.join("\n");
+
+        let result = select_diverse_lines_budgeted(&content, 1, 3001, Lang::Rust, 1_000_000);
+        assert!(result.is_some());
+
+        let kept = result.unwrap();
+        assert!(kept.contains(&1), "signature line must be kept");
+        assert!(kept.contains(&3001), "closing line must be kept");
+        assert!(kept.windows(2).all(|w| w[0] < w[1]), "lines must be sorted");
+    }
+
+    #[test]
+    fn budgeted_caps_candidate_count_for_realistic_generated_sources() {
+        // A large generated/minified function where every non-forced line
+        // scores 10 (e.g. a big dispatch table of function calls) is the
+        // worst case for the knapsack DP: without a candidate cap, `max_score`
+        // and the `choice` backpointer table both grow with line count,
+        // making the DP quadratic. This should stay fast and bounded
+        // regardless of function size.
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn huge() {".to_owned());
+        for i in 2..=20_000 {
+            lines.push(format!("    handler{i}(state, event{i});"));
+        }
+        lines.push("}".to_owned());
+        let content = lines.join("\n");
+
+        let start = std::time::Instant::now();
+        let result = select_diverse_lines_budgeted(&content, 1, 20_001, Lang::Rust, 1_000_000);
+        let elapsed = start.elapsed();
+
+        assert!(result.is_some());
+        assert!(
+            elapsed < std::time::Duration::from_secs(2),
+            "budgeted selection took too long: {elapsed:?}"
+        );
+
+        let kept = result.unwrap();
+        assert!(kept.contains(&1), "signature line must be kept");
+        assert!(kept.contains(&20_001), "closing line must be kept");
+        assert!(
+            kept.len() <= BUDGETED_KNAPSACK_MAX_CANDIDATES + 2,
+            "kept set should stay bounded by the candidate cap plus forced lines"
+        );
+    }
+
+    #[test]
+    fn mmr_spreads_across_a_dense_cluster_instead_of_taking_a_contiguous_prefix() {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn example() {".to_owned());
+        for _ in 2..=150 {
+            lines.push(String::new());
+        }
+        // A dense cluster of 60 near-identical unwrap lines, bigger than the
+        // line cap, so keeping it all is impossible and something must give.
+        for line in &mut lines[10..=69] {
+            *line = "    value.unwrap();".to_owned();
+        }
+        lines.push("}".to_owned());
+        let content = lines.join("\n");
+
+        let naive = select_diverse_lines(&content, 1, 151, Lang::Rust).unwrap();
+        let mmr =
+            select_diverse_lines_mmr(&content, 1, 151, Lang::Rust, MMR_DEFAULT_LAMBDA).unwrap();
+
+        // Greedy top-N-by-score (ties broken by ascending line) keeps a
+        // contiguous prefix of the cluster and never reaches its tail.
+        let naive_cluster_max = naive.iter().filter(|&&ln| (11..=70).contains(&ln)).max().copied();
+        assert_eq!(
+            naive_cluster_max,
+            Some(48),
+            "sanity check: greedy selection should stop partway through the cluster"
+        );
+
+        // MMR should spread its picks across the cluster's full span instead
+        // of stopping at the same point, trading a few near-duplicate lines
+        // for reach into the back of the cluster.
+        let mmr_cluster_max = mmr.iter().filter(|&&ln| (11..=70).contains(&ln)).max().copied();
+        assert!(
+            mmr_cluster_max.unwrap_or(0) > naive_cluster_max.unwrap_or(0),
+            "MMR should reach further into the cluster than greedy top-N, naive={naive_cluster_max:?} mmr={mmr_cluster_max:?}"
+        );
+    }
+
+    #[test]
+    fn mmr_keeps_signature_and_closing_line() {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn big_function() {".to_owned());
+        for i in 2..=99 {
+            lines.push(format!("    let x{i} = {i};"));
+        }
+        lines.push("}".to_owned());
+        let content = lines.join("\n");
+
+        let result = select_diverse_lines_mmr_default(&content, 1, 100, Lang::Rust).unwrap();
+
+        assert!(result.contains(&1), "signature line must be kept");
+        assert!(result.contains(&100), "closing line must be kept");
+        assert!(result.len() <= SMART_TRUNCATE_MAX_LINES);
+        assert!(result.windows(2).all(|w| w[0] < w[1]), "lines must be sorted");
+    }
+
+    #[test]
+    fn mmr_normalized_score_lets_diversity_outweigh_a_lower_score_tier() {
+        // A cluster of identical score-10 lines near the top, larger than the
+        // line cap so it could fill every free slot on its own, and a
+        // single scattered score-5 line far away. With raw (unnormalized)
+        // scores, the 10-vs-5 gap (0.5 * 5 = 2.5 at lambda=0.5) would dwarf
+        // any possible similarity swing (0.5 * 1.0 = 0.5 at most), so the
+        // scattered line could never outrank a near-duplicate cluster
+        // member. Normalizing both to `[0, 1]` lets diversity actually win
+        // once the cluster starts repeating itself.
+        let mut lines: Vec<String> = Vec::new();
+        lines.push("fn example() {".to_owned());
+        for _ in 2..=200 {
+            lines.push(String::new());
+        }
+        for line in &mut lines[4..=63] {
+            *line = "    bar.unwrap();".to_owned(); // lines 5-64, score 10
+        }
+        lines[149] = "    y = Config {".to_owned(); // line 150, score 5
+        lines.push("}".to_owned()); // line 201
+        let content = lines.join("\n");
+
+        let result = select_diverse_lines_mmr(&content, 1, 201, Lang::Rust, 0.5).unwrap();
+
+        assert!(
+            result.contains(&150),
+            "scattered lower-score line should be picked over a third near-duplicate \
+             cluster member once normalized, got {result:?}"
+        );
+    }
+
+    #[test]
+    fn mmr_short_function_returns_none() {
+        let content = (1..=50)
+            .map(|i| format!("line {i}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let result = select_diverse_lines_mmr(&content, 1, 50, Lang::Rust, MMR_DEFAULT_LAMBDA);
+        assert!(
+            result.is_none(),
+            "functions <80 lines should not be truncated"
+        );
+    }
+
+    #[test]
+    fn render_terse_merges_gaps_into_ranges() {
+        let content: Vec<String> = (1..=40).map(|i| format!("line {i}")).collect();
+        let content = content.join("\n");
+        let kept = vec![11, 12, 13, 14, 21, 31, 32, 33];
+
+        let terse = render_truncation(&content, &kept, 40, RenderMode::Terse);
+        assert_eq!(terse, "L11-L14, L21, L31-L33");
+    }
+
+    #[test]
+    fn render_pretty_interleaves_kept_blocks_with_omitted_markers() {
+        let content: Vec<String> = (1..=30).map(|i| format!("line {i}")).collect();
+        let content = content.join("\n");
+        let kept = vec![1, 2, 10, 11, 30];
+
+        let pretty = render_truncation(&content, &kept, 30, RenderMode::Pretty);
+
+        assert!(pretty.contains("line 1\nline 2\n"));
+        assert!(pretty.contains("// … 7 lines omitted …\n"));
+        assert!(pretty.contains("line 10\nline 11\n"));
+        assert!(pretty.contains("// … 18 lines omitted …\n"));
+        assert!(pretty.contains("line 30"));
+    }
+
+    #[test]
+    fn render_json_reports_kept_and_omitted_ranges() {
+        let content: Vec<String> = (1..=40).map(|i| format!("line {i}")).collect();
+        let content = content.join("\n");
+        let kept = vec![11, 12, 13, 14, 21, 31, 32, 33];
+
+        let json = render_truncation(&content, &kept, 40, RenderMode::Json);
+
+        assert_eq!(
+            json,
+            "{\"kept_ranges\":[[11,14],[21,21],[31,33]],\"omitted_ranges\":[[15,20],[22,30]],\"original_lines\":40}"
+        );
+    }
+
+    #[test]
+    fn render_json_original_lines_is_the_true_count_not_the_kept_span() {
+        // `kept` here doesn't touch the content's true first or last line, so
+        // deriving `original_lines` from `kept`'s own span would report 3
+        // instead of the real 1000.
+        let content: Vec<String> = (1..=1000).map(|i| format!("line {i}")).collect();
+        let content = content.join("\n");
+        let kept = vec![500, 501, 502];
+
+        let json = render_truncation(&content, &kept, 1000, RenderMode::Json);
+
+        assert_eq!(
+            json,
+            "{\"kept_ranges\":[[500,502]],\"omitted_ranges\":[],\"original_lines\":1000}"
+        );
+    }
+
+    #[test]
+    fn render_truncation_empty_kept_is_empty_string() {
+        let content = "line 1\nline 2\n".to_owned();
+        assert_eq!(render_truncation(&content, &[], 2, RenderMode::Pretty), "");
+        assert_eq!(render_truncation(&content, &[], 2, RenderMode::Terse), "");
+        assert_eq!(render_truncation(&content, &[], 2, RenderMode::Json), "");
+    }
 }